@@ -1,15 +1,228 @@
+use std::any::Any;
 use std::collections::HashMap;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 use bazel_protos;
 use futures;
 use grpcio;
+use protobuf;
+use sled;
 
 use bytes::Bytes;
 use futures::{Future, IntoFuture, Stream};
 use hashing::{Digest, Fingerprint};
 use testutil::data::{TestData, TestDirectory};
 
+///
+/// Storage for the blobs a StubCAS serves, abstracted so the stub can be backed by something
+/// other than an in-memory map (e.g. to persist content across process restarts in a long-running
+/// test, or to exercise behavior under a disk-backed store).
+///
+pub trait BlobService: Send + Sync {
+  fn get(&self, fingerprint: &Fingerprint) -> Option<Bytes>;
+  fn put(&self, fingerprint: Fingerprint, bytes: Bytes);
+  fn contains(&self, fingerprint: &Fingerprint) -> bool;
+  /// The number of blobs currently stored. Callers that used to inspect StubCAS.blobs directly
+  /// (e.g. to assert how much content got seeded or written) can use this instead of iterating.
+  fn len(&self) -> usize;
+  /// Lets StubCAS::blobs_map downcast back to a concrete backend for callers that still need to
+  /// reach the underlying map directly (see blobs_map's doc comment).
+  fn as_any(&self) -> &dyn Any;
+}
+
+///
+/// The default BlobService: blobs live only in this process's memory, and are lost when the
+/// StubCAS is dropped.
+///
+#[derive(Default)]
+pub struct MemoryBlobService {
+  blobs: Mutex<HashMap<Fingerprint, Bytes>>,
+}
+
+impl MemoryBlobService {
+  pub fn new(blobs: HashMap<Fingerprint, Bytes>) -> MemoryBlobService {
+    MemoryBlobService {
+      blobs: Mutex::new(blobs),
+    }
+  }
+
+  /// Direct access to the backing map, for callers that want to seed/inspect/remove blobs without
+  /// going through the BlobService trait (e.g. existing call sites that predate it). See
+  /// StubCAS::blobs_map.
+  pub fn blobs(&self) -> &Mutex<HashMap<Fingerprint, Bytes>> {
+    &self.blobs
+  }
+}
+
+impl BlobService for MemoryBlobService {
+  fn get(&self, fingerprint: &Fingerprint) -> Option<Bytes> {
+    self.blobs.lock().unwrap().get(fingerprint).cloned()
+  }
+
+  fn put(&self, fingerprint: Fingerprint, bytes: Bytes) {
+    self.blobs.lock().unwrap().insert(fingerprint, bytes);
+  }
+
+  fn contains(&self, fingerprint: &Fingerprint) -> bool {
+    self.blobs.lock().unwrap().contains_key(fingerprint)
+  }
+
+  fn len(&self) -> usize {
+    self.blobs.lock().unwrap().len()
+  }
+
+  fn as_any(&self) -> &dyn Any {
+    self
+  }
+}
+
+///
+/// A BlobService backed by an on-disk sled tree, keyed by the hex-encoded fingerprint. Useful for
+/// integration tests which want a populated CAS to survive between test phases (or processes).
+///
+pub struct SledBlobService {
+  db: sled::Db,
+}
+
+impl SledBlobService {
+  pub fn new<P: AsRef<Path>>(path: P) -> SledBlobService {
+    let db =
+      sled::open(path).unwrap_or_else(|err| panic!("Failed to open sled database for StubCAS: {}", err));
+    SledBlobService { db }
+  }
+}
+
+impl BlobService for SledBlobService {
+  fn get(&self, fingerprint: &Fingerprint) -> Option<Bytes> {
+    self
+      .db
+      .get(fingerprint.to_hex().into_bytes())
+      .expect("Error reading from sled")
+      .map(|value| Bytes::from(value.to_vec()))
+  }
+
+  fn put(&self, fingerprint: Fingerprint, bytes: Bytes) {
+    self
+      .db
+      .insert(fingerprint.to_hex().into_bytes(), bytes.to_vec())
+      .expect("Error writing to sled");
+  }
+
+  fn contains(&self, fingerprint: &Fingerprint) -> bool {
+    self
+      .db
+      .contains_key(fingerprint.to_hex().into_bytes())
+      .expect("Error reading from sled")
+  }
+
+  fn len(&self) -> usize {
+    self.db.len()
+  }
+
+  fn as_any(&self) -> &dyn Any {
+    self
+  }
+}
+
+///
+/// Describes a transient failure mode to inject into one RPC, so client retry/backoff logic can
+/// be exercised against something other than an always-on or always-off stub. The first
+/// `fail_attempts` calls are failed with `status_code`; calls after that succeed normally (modulo
+/// `truncate_after_chunks`, for streamed reads). `attempts` is shared (via Arc) across clones of
+/// the StubCASResponder that serves a given RPC, so it accumulates across the whole lifetime of
+/// the StubCAS.
+///
+#[derive(Clone)]
+pub struct Fault {
+  pub status_code: grpcio::RpcStatusCode,
+  pub message: String,
+  pub fail_attempts: usize,
+  /// A fixed delay to sleep before responding (on both failing and succeeding attempts).
+  pub delay: Option<Duration>,
+  /// For streamed reads only: once this Fault stops failing outright, still only emit this many
+  /// response chunks before erroring out, to simulate a connection dropped mid-stream.
+  pub truncate_after_chunks: Option<usize>,
+  attempts: Arc<Mutex<usize>>,
+}
+
+impl Fault {
+  pub fn new(status_code: grpcio::RpcStatusCode, fail_attempts: usize) -> Fault {
+    Fault {
+      message: format!("StubCAS is configured to fail with {:?}", status_code),
+      status_code,
+      fail_attempts,
+      delay: None,
+      truncate_after_chunks: None,
+      attempts: Arc::new(Mutex::new(0)),
+    }
+  }
+
+  pub fn with_delay(mut self, delay: Duration) -> Fault {
+    self.delay = Some(delay);
+    self
+  }
+
+  pub fn with_truncate_after_chunks(mut self, chunks: usize) -> Fault {
+    self.truncate_after_chunks = Some(chunks);
+    self
+  }
+
+  pub fn attempt_count(&self) -> usize {
+    *self.attempts.lock().unwrap()
+  }
+
+  ///
+  /// Records an attempt (sleeping for `delay` first, if set), and returns the error this attempt
+  /// should fail with, if any.
+  ///
+  fn on_attempt(&self) -> Option<grpcio::RpcStatus> {
+    let this_attempt = {
+      let mut attempts = self.attempts.lock().unwrap();
+      *attempts += 1;
+      *attempts
+    };
+    if let Some(delay) = self.delay {
+      thread::sleep(delay);
+    }
+    if this_attempt <= self.fail_attempts {
+      Some(grpcio::RpcStatus::new(
+        self.status_code,
+        Some(self.message.clone()),
+      ))
+    } else {
+      None
+    }
+  }
+}
+
+///
+/// Per-operation Faults to inject. Defaults to injecting nothing, i.e. behaving as if no
+/// FaultConfig had been supplied at all.
+///
+#[derive(Clone, Default)]
+pub struct FaultConfig {
+  pub read: Option<Fault>,
+  pub write: Option<Fault>,
+  pub find_missing_blobs: Option<Fault>,
+}
+
+impl FaultConfig {
+  ///
+  /// Whether any configured Fault sleeps before responding. With only one completion-queue
+  /// thread, a delay on the CQ thread would stall every RPC on this server (not just the faulty
+  /// one), so with_blob_service_and_port_and_cred uses this to decide whether to give the server
+  /// more than one.
+  ///
+  fn has_delay(&self) -> bool {
+    [&self.read, &self.write, &self.find_missing_blobs]
+      .iter()
+      .any(|fault| fault.as_ref().map_or(false, |fault| fault.delay.is_some()))
+  }
+}
+
 ///
 /// Implements the ContentAddressableStorage gRPC API, answering read requests with either known
 /// content, NotFound for valid but unknown content, or InvalidArguments for bad arguments.
@@ -18,7 +231,9 @@ pub struct StubCAS {
   server_transport: grpcio::Server,
   read_request_count: Arc<Mutex<usize>>,
   pub write_message_sizes: Arc<Mutex<Vec<usize>>>,
-  pub blobs: Arc<Mutex<HashMap<Fingerprint, Bytes>>>,
+  pub blobs: Arc<dyn BlobService>,
+  pub partial_writes: Arc<Mutex<HashMap<String, Bytes>>>,
+  secure: bool,
 }
 
 impl StubCAS {
@@ -37,6 +252,25 @@ impl StubCAS {
     StubCAS::with_unverified_content(chunk_size_bytes, blobs)
   }
 
+  ///
+  /// Like with_content, but also verifies the fingerprint of the seeded content at construction
+  /// time, and of anything subsequently uploaded to it.
+  ///
+  pub fn with_content_verifying(
+    chunk_size_bytes: i64,
+    files: Vec<TestData>,
+    directories: Vec<TestDirectory>,
+  ) -> StubCAS {
+    let mut blobs = HashMap::new();
+    for file in files {
+      blobs.insert(file.fingerprint(), file.bytes());
+    }
+    for directory in directories {
+      blobs.insert(directory.fingerprint(), directory.bytes());
+    }
+    StubCAS::with_verified_content(chunk_size_bytes, blobs)
+  }
+
   ///
   /// Wrapper around with_unverified_content_and_port
   ///
@@ -61,26 +295,200 @@ impl StubCAS {
     blobs: HashMap<Fingerprint, Bytes>,
     port: u16,
   ) -> StubCAS {
-    let env = Arc::new(grpcio::Environment::new(1));
+    StubCAS::with_content_and_port(chunk_size_bytes, blobs, port, false)
+  }
+
+  ///
+  /// Like with_unverified_content, but also verifies the seeded blobs at construction time, and
+  /// causes the server to verify the fingerprint of anything subsequently uploaded to it. See
+  /// with_content_verifying for the analogous wrapper around with_content.
+  ///
+  pub fn with_verified_content(
+    chunk_size_bytes: i64,
+    blobs: HashMap<Fingerprint, Bytes>,
+  ) -> StubCAS {
+    StubCAS::with_verified_content_and_port(chunk_size_bytes, blobs, 0)
+  }
+
+  pub fn with_verified_content_and_port(
+    chunk_size_bytes: i64,
+    blobs: HashMap<Fingerprint, Bytes>,
+    port: u16,
+  ) -> StubCAS {
+    StubCAS::with_content_and_port(chunk_size_bytes, blobs, port, true)
+  }
+
+  fn with_content_and_port(
+    chunk_size_bytes: i64,
+    blobs: HashMap<Fingerprint, Bytes>,
+    port: u16,
+    verify_digests: bool,
+  ) -> StubCAS {
+    StubCAS::with_content_and_port_and_cred(chunk_size_bytes, blobs, port, verify_digests, None)
+  }
+
+  ///
+  /// # Arguments
+  /// * `chunk_size_bytes`          - As with with_unverified_content_and_port.
+  /// * `blobs`                     - As with with_unverified_content_and_port. Not verified.
+  /// * `server_cert_pem`           - PEM-encoded X509 certificate the server will present.
+  /// * `server_key_pem`            - PEM-encoded private key matching server_cert_pem.
+  /// * `client_root_ca_cert_pem`   - If given, the PEM-encoded CA certificate used to verify
+  ///                                 client certificates. When set, the server requires and
+  ///                                 verifies a client certificate (mutual TLS); when omitted,
+  ///                                 the server only authenticates itself to the client.
+  ///
+  pub fn with_tls(
+    chunk_size_bytes: i64,
+    blobs: HashMap<Fingerprint, Bytes>,
+    server_cert_pem: Vec<u8>,
+    server_key_pem: Vec<u8>,
+    client_root_ca_cert_pem: Option<Vec<u8>>,
+  ) -> StubCAS {
+    StubCAS::with_tls_and_port(
+      chunk_size_bytes,
+      blobs,
+      0,
+      server_cert_pem,
+      server_key_pem,
+      client_root_ca_cert_pem,
+    )
+  }
+
+  pub fn with_tls_and_port(
+    chunk_size_bytes: i64,
+    blobs: HashMap<Fingerprint, Bytes>,
+    port: u16,
+    server_cert_pem: Vec<u8>,
+    server_key_pem: Vec<u8>,
+    client_root_ca_cert_pem: Option<Vec<u8>>,
+  ) -> StubCAS {
+    let mut cred_builder =
+      grpcio::ServerCredentialsBuilder::new().add_cert(server_cert_pem, server_key_pem);
+    if let Some(client_root_ca_cert_pem) = client_root_ca_cert_pem {
+      cred_builder = cred_builder.root_cert(
+        client_root_ca_cert_pem,
+        grpcio::CertificateRequestType::RequireAndVerifyClientCert,
+      );
+    }
+    StubCAS::with_content_and_port_and_cred(
+      chunk_size_bytes,
+      blobs,
+      port,
+      false,
+      Some(cred_builder.build()),
+    )
+  }
+
+  ///
+  /// Like with_unverified_content, but backs the CAS with an on-disk sled tree rather than an
+  /// in-memory map, so content survives across StubCAS instances pointed at the same path (e.g.
+  /// between phases of a single test, or across process restarts).
+  ///
+  pub fn with_sled_backend(path: impl AsRef<Path>, chunk_size_bytes: i64) -> StubCAS {
+    StubCAS::with_blob_service_and_port_and_cred(
+      chunk_size_bytes,
+      Arc::new(SledBlobService::new(path)),
+      0,
+      false,
+      None,
+      FaultConfig::default(),
+    )
+  }
+
+  ///
+  /// Like with_unverified_content, but injects the given per-operation Faults (transient
+  /// failures, delays, or truncated read streams) so tests can exercise client retry/backoff
+  /// logic. See FaultConfig.
+  ///
+  pub fn with_fault_config(
+    chunk_size_bytes: i64,
+    blobs: HashMap<Fingerprint, Bytes>,
+    fault_config: FaultConfig,
+  ) -> StubCAS {
+    StubCAS::with_fault_config_and_port(chunk_size_bytes, blobs, 0, fault_config)
+  }
+
+  pub fn with_fault_config_and_port(
+    chunk_size_bytes: i64,
+    blobs: HashMap<Fingerprint, Bytes>,
+    port: u16,
+    fault_config: FaultConfig,
+  ) -> StubCAS {
+    StubCAS::with_blob_service_and_port_and_cred(
+      chunk_size_bytes,
+      Arc::new(MemoryBlobService::new(blobs)),
+      port,
+      false,
+      None,
+      fault_config,
+    )
+  }
+
+  fn with_content_and_port_and_cred(
+    chunk_size_bytes: i64,
+    blobs: HashMap<Fingerprint, Bytes>,
+    port: u16,
+    verify_digests: bool,
+    server_cred: Option<grpcio::ServerCredentials>,
+  ) -> StubCAS {
+    if verify_digests {
+      for (fingerprint, bytes) in &blobs {
+        StubCASResponder::check_fingerprint(*fingerprint, bytes)
+          .unwrap_or_else(|err| panic!("Failed to seed StubCAS with valid content: {}", err));
+      }
+    }
+    StubCAS::with_blob_service_and_port_and_cred(
+      chunk_size_bytes,
+      Arc::new(MemoryBlobService::new(blobs)),
+      port,
+      verify_digests,
+      server_cred,
+      FaultConfig::default(),
+    )
+  }
+
+  fn with_blob_service_and_port_and_cred(
+    chunk_size_bytes: i64,
+    blobs: Arc<dyn BlobService>,
+    port: u16,
+    verify_digests: bool,
+    server_cred: Option<grpcio::ServerCredentials>,
+    fault_config: FaultConfig,
+  ) -> StubCAS {
+    let secure = server_cred.is_some();
+
+    // A delay-bearing Fault sleeps on whichever completion-queue thread picked up its RPC. With
+    // only one such thread, that sleep would serialize all other traffic on this server behind
+    // it, defeating the point of injecting a delay on only one RPC. Give the server a few more
+    // threads whenever any configured Fault has a delay, so unrelated RPCs can still be dispatched
+    // concurrently.
+    let cq_threads = if fault_config.has_delay() { 4 } else { 1 };
+    let env = Arc::new(grpcio::Environment::new(cq_threads));
     let read_request_count = Arc::new(Mutex::new(0));
     let write_message_sizes = Arc::new(Mutex::new(Vec::new()));
-    let blobs = Arc::new(Mutex::new(blobs));
+    let partial_writes = Arc::new(Mutex::new(HashMap::new()));
     let responder = StubCASResponder {
       chunk_size_bytes: chunk_size_bytes,
       blobs: blobs.clone(),
       read_request_count: read_request_count.clone(),
       write_message_sizes: write_message_sizes.clone(),
+      partial_writes: partial_writes.clone(),
+      verify_digests,
+      fault_config,
     };
-    let mut server_transport = grpcio::ServerBuilder::new(env)
+    let mut server_builder = grpcio::ServerBuilder::new(env)
       .register_service(bazel_protos::bytestream_grpc::create_byte_stream(
         responder.clone(),
       ))
       .register_service(
         bazel_protos::remote_execution_grpc::create_content_addressable_storage(responder.clone()),
-      )
-      .bind("localhost", port)
-      .build()
-      .unwrap();
+      );
+    server_builder = match server_cred {
+      Some(cred) => server_builder.bind_with_cred("localhost", port, cred),
+      None => server_builder.bind("localhost", port),
+    };
+    let mut server_transport = server_builder.build().unwrap();
     server_transport.start();
 
     StubCAS {
@@ -88,6 +496,8 @@ impl StubCAS {
       read_request_count,
       write_message_sizes,
       blobs,
+      partial_writes,
+      secure,
     }
   }
 
@@ -112,24 +522,69 @@ impl StubCAS {
   }
 
   ///
-  /// The address on which this server is listening over insecure HTTP transport.
+  /// The address on which this server is listening, over HTTP or, if constructed via with_tls,
+  /// TLS. See is_secure to tell which.
   ///
   pub fn address(&self) -> String {
     let bind_addr = self.server_transport.bind_addrs().first().unwrap();
     format!("{}:{}", bind_addr.0, bind_addr.1)
   }
 
+  ///
+  /// Whether this server requires a TLS transport (i.e. was constructed via with_tls).
+  ///
+  pub fn is_secure(&self) -> bool {
+    self.secure
+  }
+
   pub fn read_request_count(&self) -> usize {
     *self.read_request_count.lock().unwrap()
   }
+
+  ///
+  /// Back-compat escape hatch for callers that reach into the backing map directly (seeding extra
+  /// blobs, counting entries, removing one) rather than going through the BlobService trait,
+  /// predating blobs becoming pluggable. Only available when this StubCAS is backed by the
+  /// default MemoryBlobService (i.e. not constructed via with_sled_backend); panics otherwise,
+  /// since there's no map to hand back.
+  ///
+  pub fn blobs_map(&self) -> &Mutex<HashMap<Fingerprint, Bytes>> {
+    self
+      .blobs
+      .as_any()
+      .downcast_ref::<MemoryBlobService>()
+      .expect("blobs_map() is only available when StubCAS is backed by MemoryBlobService")
+      .blobs()
+  }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub struct StubCASResponder {
   chunk_size_bytes: i64,
-  blobs: Arc<Mutex<HashMap<Fingerprint, Bytes>>>,
+  blobs: Arc<dyn BlobService>,
   pub read_request_count: Arc<Mutex<usize>>,
   pub write_message_sizes: Arc<Mutex<Vec<usize>>>,
+  // Bytes committed so far for writes that haven't yet seen a WriteRequest with
+  // finish_write=true, keyed by resource_name. A resumed write looks itself up here to pick up
+  // where the previous stream left off; query_write_status reports straight out of this map.
+  partial_writes: Arc<Mutex<HashMap<String, Bytes>>>,
+  verify_digests: bool,
+  fault_config: FaultConfig,
+}
+
+impl std::fmt::Debug for StubCASResponder {
+  // BlobService is a trait object and so isn't Debug; report its blob count instead so this impl
+  // (dropped when blobs became pluggable) still lets callers format!("{:?}", responder) for
+  // debugging/assertion messages.
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    f.debug_struct("StubCASResponder")
+      .field("chunk_size_bytes", &self.chunk_size_bytes)
+      .field("blobs.len()", &self.blobs.len())
+      .field("read_request_count", &self.read_request_count)
+      .field("write_message_sizes", &self.write_message_sizes)
+      .field("verify_digests", &self.verify_digests)
+      .finish()
+  }
 }
 
 impl StubCASResponder {
@@ -164,8 +619,7 @@ impl StubCASResponder {
         Some("StubCAS is configured to always fail".to_owned()),
       ));
     }
-    let blobs = self.blobs.lock().unwrap();
-    let maybe_bytes = blobs.get(&fingerprint);
+    let maybe_bytes = self.blobs.get(&fingerprint);
     match maybe_bytes {
       Some(bytes) => Ok(
         bytes
@@ -184,6 +638,61 @@ impl StubCASResponder {
     }
   }
 
+  fn status_proto(code: grpcio::RpcStatusCode, message: String) -> bazel_protos::google::rpc::Status {
+    let mut status = bazel_protos::google::rpc::Status::new();
+    status.set_code(code as i32);
+    status.set_message(message);
+    status
+  }
+
+  fn status_ok() -> bazel_protos::google::rpc::Status {
+    StubCASResponder::status_proto(grpcio::RpcStatusCode::Ok, String::new())
+  }
+
+  ///
+  /// Checks that the size of some uploaded bytes matches the size claimed by the digest, and,
+  /// when verify_digests is set, that its SHA256 fingerprint does too. Shared between the
+  /// ByteStream write path and BatchUpdateBlobs, which both learn of a digest from a different
+  /// source (a resource name vs. a Digest message) but must apply the same check.
+  ///
+  fn check_digest(
+    digest: Digest,
+    bytes: &Bytes,
+    verify_digests: bool,
+  ) -> Result<(), grpcio::RpcStatus> {
+    if digest.1 != bytes.len() {
+      return Err(grpcio::RpcStatus::new(
+        grpcio::RpcStatusCode::InvalidArgument,
+        Some(format!(
+          "Size was incorrect: digest said size={} but got {}",
+          digest.1,
+          bytes.len()
+        )),
+      ));
+    }
+    if verify_digests {
+      StubCASResponder::check_fingerprint(digest.0, bytes).map_err(|err| {
+        grpcio::RpcStatus::new(grpcio::RpcStatusCode::InvalidArgument, Some(err))
+      })?;
+    }
+    Ok(())
+  }
+
+  ///
+  /// Recomputes the SHA256 fingerprint of some bytes and compares it to what was claimed for
+  /// them, so callers can catch a client (or a test fixture) that got its digest wrong.
+  ///
+  fn check_fingerprint(claimed_fingerprint: Fingerprint, bytes: &Bytes) -> Result<(), String> {
+    let actual_fingerprint = Fingerprint::from_bytes_unsafe(bytes);
+    if claimed_fingerprint != actual_fingerprint {
+      return Err(format!(
+        "Fingerprint was incorrect: claimed fingerprint {} did not match actual fingerprint {}",
+        claimed_fingerprint, actual_fingerprint
+      ));
+    }
+    Ok(())
+  }
+
   ///
   /// Sends a stream of responses down a sink, in ctx's threadpool.
   ///
@@ -211,16 +720,43 @@ impl bazel_protos::bytestream_grpc::ByteStream for StubCASResponder {
       let mut request_count = self.read_request_count.lock().unwrap();
       *request_count += 1;
     }
+    if let Some(ref fault) = self.fault_config.read {
+      if let Some(status) = fault.on_attempt() {
+        sink.fail(status);
+        return;
+      }
+    }
     match self.read_internal(&req) {
-      Ok(response) => self.send(
-        &ctx,
-        sink,
-        futures::stream::iter_ok(
-          response
-            .into_iter()
-            .map(|chunk| (chunk, grpcio::WriteFlags::default())),
-        ),
-      ),
+      Ok(response) => {
+        let truncate_after_chunks = self
+          .fault_config
+          .read
+          .as_ref()
+          .and_then(|fault| fault.truncate_after_chunks);
+        match truncate_after_chunks {
+          Some(truncate_after) if truncate_after < response.len() => {
+            let mut items: Vec<Result<_, grpcio::Error>> = response[..truncate_after]
+              .iter()
+              .cloned()
+              .map(|chunk| Ok((chunk, grpcio::WriteFlags::default())))
+              .collect();
+            items.push(Err(grpcio::Error::RpcFailure(grpcio::RpcStatus::new(
+              grpcio::RpcStatusCode::Unavailable,
+              Some("StubCAS is configured to drop the stream partway through".to_owned()),
+            ))));
+            self.send(&ctx, sink, futures::stream::iter_result(items));
+          }
+          _ => self.send(
+            &ctx,
+            sink,
+            futures::stream::iter_ok(
+              response
+                .into_iter()
+                .map(|chunk| (chunk, grpcio::WriteFlags::default())),
+            ),
+          ),
+        }
+      }
       Err(err) => {
         sink.fail(err);
       }
@@ -233,9 +769,17 @@ impl bazel_protos::bytestream_grpc::ByteStream for StubCASResponder {
     stream: grpcio::RequestStream<bazel_protos::bytestream::WriteRequest>,
     sink: grpcio::ClientStreamingSink<bazel_protos::bytestream::WriteResponse>,
   ) {
+    if let Some(ref fault) = self.fault_config.write {
+      if let Some(status) = fault.on_attempt() {
+        sink.fail(status);
+        return;
+      }
+    }
     let should_always_fail = self.should_always_fail();
     let write_message_sizes = self.write_message_sizes.clone();
     let blobs = self.blobs.clone();
+    let partial_writes = self.partial_writes.clone();
+    let verify_digests = self.verify_digests;
     ctx.spawn(
       stream
         .collect()
@@ -244,9 +788,31 @@ impl bazel_protos::bytestream_grpc::ByteStream for StubCASResponder {
           let mut maybe_resource_name = None;
           let mut want_next_offset = 0;
           let mut bytes = Bytes::new();
+          let mut finished = false;
           for req in reqs {
             match maybe_resource_name {
-              None => maybe_resource_name = Some(req.get_resource_name().to_owned()),
+              None => {
+                let resource_name = req.get_resource_name().to_owned();
+                if req.get_write_offset() != 0 {
+                  match partial_writes.lock().unwrap().get(&resource_name) {
+                    Some(partial) if (req.get_write_offset() as usize) <= partial.len() => {
+                      bytes = partial.slice_to(req.get_write_offset() as usize);
+                      want_next_offset = req.get_write_offset();
+                    }
+                    _ => {
+                      return Err(grpcio::Error::RpcFailure(grpcio::RpcStatus::new(
+                        grpcio::RpcStatusCode::InvalidArgument,
+                        Some(format!(
+                          "Cannot resume write to {} at offset {}: past what is committed for it",
+                          resource_name,
+                          req.get_write_offset()
+                        )),
+                      )));
+                    }
+                  }
+                }
+                maybe_resource_name = Some(resource_name);
+              }
               Some(ref resource_name) => {
                 if resource_name != req.get_resource_name() {
                   return Err(grpcio::Error::RpcFailure(grpcio::RpcStatus::new(
@@ -276,15 +842,16 @@ impl bazel_protos::bytestream_grpc::ByteStream for StubCASResponder {
               .unwrap()
               .push(req.get_data().len());
             bytes.extend(req.get_data());
+            finished = req.get_finish_write();
           }
-          Ok((maybe_resource_name, bytes))
+          Ok((maybe_resource_name, bytes, finished))
         })
         .map_err(move |err: grpcio::Error| match err {
           grpcio::Error::RpcFailure(status) => status,
           e => grpcio::RpcStatus::new(grpcio::RpcStatusCode::Unknown, Some(format!("{:?}", e))),
         })
         .and_then(
-          move |(maybe_resource_name, bytes)| match maybe_resource_name {
+          move |(maybe_resource_name, bytes, finished)| match maybe_resource_name {
             None => Err(grpcio::RpcStatus::new(
               grpcio::RpcStatusCode::InvalidArgument,
               Some("Stream saw no messages".to_owned()),
@@ -321,16 +888,6 @@ impl bazel_protos::bytestream_grpc::ByteStream for StubCASResponder {
                   ))
                 }
               };
-              if size != bytes.len() {
-                return Err(grpcio::RpcStatus::new(
-                  grpcio::RpcStatusCode::InvalidArgument,
-                  Some(format!(
-                    "Size was incorrect: resource name said size={} but got {}",
-                    size,
-                    bytes.len()
-                  )),
-                ));
-              }
 
               if should_always_fail {
                 return Err(grpcio::RpcStatus::new(
@@ -339,11 +896,26 @@ impl bazel_protos::bytestream_grpc::ByteStream for StubCASResponder {
                 ));
               }
 
+              if !finished {
+                let committed_size = bytes.len() as i64;
+                partial_writes
+                  .lock()
+                  .unwrap()
+                  .insert(resource_name, bytes);
+                let mut response = bazel_protos::bytestream::WriteResponse::new();
+                response.set_committed_size(committed_size);
+                return Ok(response);
+              }
+
+              if let Err(status) =
+                StubCASResponder::check_digest(Digest(fingerprint, size), &bytes, verify_digests)
               {
-                let mut blobs = blobs.lock().unwrap();
-                blobs.insert(fingerprint, bytes);
+                return Err(status);
               }
 
+              blobs.put(fingerprint, bytes);
+              partial_writes.lock().unwrap().remove(&resource_name);
+
               let mut response = bazel_protos::bytestream::WriteResponse::new();
               response.set_committed_size(size as i64);
               Ok(response)
@@ -361,13 +933,41 @@ impl bazel_protos::bytestream_grpc::ByteStream for StubCASResponder {
   fn query_write_status(
     &self,
     _ctx: grpcio::RpcContext,
-    _req: bazel_protos::bytestream::QueryWriteStatusRequest,
+    req: bazel_protos::bytestream::QueryWriteStatusRequest,
     sink: grpcio::UnarySink<bazel_protos::bytestream::QueryWriteStatusResponse>,
   ) {
-    sink.fail(grpcio::RpcStatus::new(
-      grpcio::RpcStatusCode::Unimplemented,
-      None,
-    ));
+    if self.should_always_fail() {
+      sink.fail(grpcio::RpcStatus::new(
+        grpcio::RpcStatusCode::Internal,
+        Some("StubCAS is configured to always fail".to_owned()),
+      ));
+      return;
+    }
+    let resource_name = req.get_resource_name();
+    if let Some(partial) = self.partial_writes.lock().unwrap().get(resource_name) {
+      let mut response = bazel_protos::bytestream::QueryWriteStatusResponse::new();
+      response.set_committed_size(partial.len() as i64);
+      response.set_complete(false);
+      sink.success(response);
+      return;
+    }
+    let parts: Vec<_> = resource_name.splitn(6, '/').collect();
+    let landed_size = parts
+      .get(4)
+      .and_then(|hex| Fingerprint::from_hex_string(hex).ok())
+      .and_then(|fingerprint| self.blobs.get(&fingerprint).map(|bytes| bytes.len()));
+    match landed_size {
+      Some(size) => {
+        let mut response = bazel_protos::bytestream::QueryWriteStatusResponse::new();
+        response.set_committed_size(size as i64);
+        response.set_complete(true);
+        sink.success(response);
+      }
+      None => sink.fail(grpcio::RpcStatus::new(
+        grpcio::RpcStatusCode::NotFound,
+        Some(format!("Did not find resource {}", resource_name)),
+      )),
+    }
   }
 }
 
@@ -378,6 +978,12 @@ impl bazel_protos::remote_execution_grpc::ContentAddressableStorage for StubCASR
     req: bazel_protos::remote_execution::FindMissingBlobsRequest,
     sink: grpcio::UnarySink<bazel_protos::remote_execution::FindMissingBlobsResponse>,
   ) {
+    if let Some(ref fault) = self.fault_config.find_missing_blobs {
+      if let Some(status) = fault.on_attempt() {
+        sink.fail(status);
+        return;
+      }
+    }
     if self.should_always_fail() {
       sink.fail(grpcio::RpcStatus::new(
         grpcio::RpcStatusCode::Internal,
@@ -385,12 +991,11 @@ impl bazel_protos::remote_execution_grpc::ContentAddressableStorage for StubCASR
       ));
       return;
     }
-    let blobs = self.blobs.lock().unwrap();
     let mut response = bazel_protos::remote_execution::FindMissingBlobsResponse::new();
     for digest in req.get_blob_digests() {
       let hashing_digest_result: Result<Digest, String> = digest.into();
       let hashing_digest = hashing_digest_result.expect("Bad digest");
-      if !blobs.contains_key(&hashing_digest.0) {
+      if !self.blobs.contains(&hashing_digest.0) {
         response.mut_missing_blob_digests().push(digest.clone())
       }
     }
@@ -400,22 +1005,632 @@ impl bazel_protos::remote_execution_grpc::ContentAddressableStorage for StubCASR
   fn batch_update_blobs(
     &self,
     _ctx: grpcio::RpcContext,
-    _req: bazel_protos::remote_execution::BatchUpdateBlobsRequest,
+    req: bazel_protos::remote_execution::BatchUpdateBlobsRequest,
     sink: grpcio::UnarySink<bazel_protos::remote_execution::BatchUpdateBlobsResponse>,
   ) {
-    sink.fail(grpcio::RpcStatus::new(
-      grpcio::RpcStatusCode::Unimplemented,
-      None,
-    ));
+    if self.should_always_fail() {
+      sink.fail(grpcio::RpcStatus::new(
+        grpcio::RpcStatusCode::Internal,
+        Some("StubCAS is configured to always fail".to_owned()),
+      ));
+      return;
+    }
+    let mut response = bazel_protos::remote_execution::BatchUpdateBlobsResponse::new();
+    for blob_request in req.get_requests() {
+      let digest = blob_request.get_digest();
+      let mut blob_response = bazel_protos::remote_execution::BatchUpdateBlobsResponse_Response::new();
+      blob_response.set_digest(digest.clone());
+      let hashing_digest_result: Result<Digest, String> = digest.into();
+      let status = match hashing_digest_result {
+        Ok(hashing_digest) => {
+          let data = Bytes::from(blob_request.get_data());
+          match StubCASResponder::check_digest(hashing_digest, &data, self.verify_digests) {
+            Ok(()) => {
+              self.blobs.put(hashing_digest.0, data);
+              StubCASResponder::status_ok()
+            }
+            Err(err) => StubCASResponder::status_proto(err.status, err.details.unwrap_or_default()),
+          }
+        }
+        Err(err) => StubCASResponder::status_proto(
+          grpcio::RpcStatusCode::InvalidArgument,
+          format!("Bad digest: {}", err),
+        ),
+      };
+      blob_response.set_status(status);
+      response.mut_responses().push(blob_response);
+    }
+    sink.success(response);
   }
-  fn get_tree(
+
+  fn batch_read_blobs(
     &self,
     _ctx: grpcio::RpcContext,
-    _req: bazel_protos::remote_execution::GetTreeRequest,
-    _sink: grpcio::ServerStreamingSink<bazel_protos::remote_execution::GetTreeResponse>,
+    req: bazel_protos::remote_execution::BatchReadBlobsRequest,
+    sink: grpcio::UnarySink<bazel_protos::remote_execution::BatchReadBlobsResponse>,
   ) {
-    // Our client doesn't currently use get_tree, so we don't bother implementing it.
-    // We will need to if the client starts wanting to use it.
-    unimplemented!()
+    if self.should_always_fail() {
+      sink.fail(grpcio::RpcStatus::new(
+        grpcio::RpcStatusCode::Internal,
+        Some("StubCAS is configured to always fail".to_owned()),
+      ));
+      return;
+    }
+    let mut response = bazel_protos::remote_execution::BatchReadBlobsResponse::new();
+    for digest in req.get_digests() {
+      let mut blob_response = bazel_protos::remote_execution::BatchReadBlobsResponse_Response::new();
+      blob_response.set_digest(digest.clone());
+      let hashing_digest_result: Result<Digest, String> = digest.into();
+      let status = match hashing_digest_result {
+        Ok(hashing_digest) => match self.blobs.get(&hashing_digest.0) {
+          Some(bytes) => {
+            blob_response.set_data(bytes);
+            StubCASResponder::status_ok()
+          }
+          None => StubCASResponder::status_proto(
+            grpcio::RpcStatusCode::NotFound,
+            format!("Did not find digest {}", hashing_digest.0),
+          ),
+        },
+        Err(err) => StubCASResponder::status_proto(
+          grpcio::RpcStatusCode::InvalidArgument,
+          format!("Bad digest: {}", err),
+        ),
+      };
+      blob_response.set_status(status);
+      response.mut_responses().push(blob_response);
+    }
+    sink.success(response);
+  }
+
+  fn get_tree(
+    &self,
+    ctx: grpcio::RpcContext,
+    req: bazel_protos::remote_execution::GetTreeRequest,
+    sink: grpcio::ServerStreamingSink<bazel_protos::remote_execution::GetTreeResponse>,
+  ) {
+    if self.should_always_fail() {
+      sink.fail(grpcio::RpcStatus::new(
+        grpcio::RpcStatusCode::Internal,
+        Some("StubCAS is configured to always fail".to_owned()),
+      ));
+      return;
+    }
+
+    let mut directories = Vec::new();
+    let mut visited = std::collections::HashSet::new();
+    let mut to_visit = vec![req.get_root_digest().clone()];
+    while let Some(digest) = to_visit.pop() {
+      let hashing_digest_result: Result<Digest, String> = (&digest).into();
+      let hashing_digest = match hashing_digest_result {
+        Ok(d) => d,
+        Err(err) => {
+          sink.fail(grpcio::RpcStatus::new(
+            grpcio::RpcStatusCode::InvalidArgument,
+            Some(format!("Bad digest: {}", err)),
+          ));
+          return;
+        }
+      };
+      if !visited.insert(hashing_digest.0) {
+        // Already fetched this directory via another parent that references the same (deduped)
+        // subtree; don't emit or traverse it a second time.
+        continue;
+      }
+      let bytes = match self.blobs.get(&hashing_digest.0) {
+        Some(bytes) => bytes,
+        None => {
+          sink.fail(grpcio::RpcStatus::new(
+            grpcio::RpcStatusCode::NotFound,
+            Some(format!("Did not find digest {}", hashing_digest.0)),
+          ));
+          return;
+        }
+      };
+      let directory: bazel_protos::remote_execution::Directory =
+        match protobuf::parse_from_bytes(&bytes) {
+          Ok(d) => d,
+          Err(err) => {
+            sink.fail(grpcio::RpcStatus::new(
+              grpcio::RpcStatusCode::InvalidArgument,
+              Some(format!(
+                "Failed to parse Directory for digest {}: {}",
+                hashing_digest.0, err
+              )),
+            ));
+            return;
+          }
+        };
+      for child in directory.get_directories() {
+        to_visit.push(child.get_digest().clone());
+      }
+      directories.push(directory);
+    }
+
+    let page_size = if req.get_page_size() > 0 {
+      req.get_page_size() as usize
+    } else {
+      directories.len().max(1)
+    };
+    let start = req
+      .get_page_token()
+      .parse::<usize>()
+      .unwrap_or(0)
+      .min(directories.len());
+    let end = (start + page_size).min(directories.len());
+
+    let mut response = bazel_protos::remote_execution::GetTreeResponse::new();
+    for directory in &directories[start..end] {
+      response.mut_directories().push(directory.clone());
+    }
+    if end < directories.len() {
+      response.set_next_page_token(end.to_string());
+    }
+
+    self.send(
+      &ctx,
+      sink,
+      futures::stream::iter_ok(vec![(response, grpcio::WriteFlags::default())]),
+    );
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use bazel_protos::bytestream_grpc::ByteStreamClient;
+  use bazel_protos::remote_execution_grpc::ContentAddressableStorageClient;
+
+  fn channel(cas: &StubCAS) -> grpcio::Channel {
+    let env = Arc::new(grpcio::Environment::new(1));
+    grpcio::ChannelBuilder::new(env).connect(&cas.address())
+  }
+
+  #[test]
+  fn batch_update_blobs_reports_per_entry_status_rather_than_failing_whole_rpc() {
+    let cas = StubCAS::empty();
+    let client = ContentAddressableStorageClient::new(channel(&cas));
+
+    let good = TestData::roland();
+    let mut good_digest = bazel_protos::remote_execution::Digest::new();
+    good_digest.set_hash(good.fingerprint().to_hex());
+    good_digest.set_size_bytes(good.len() as i64);
+
+    // A size that doesn't match the data's actual length, so this entry fails check_digest
+    // without affecting the other entry in the same batch.
+    let mut bad_digest = good_digest.clone();
+    bad_digest.set_size_bytes(good.len() as i64 + 1);
+
+    let mut good_request = bazel_protos::remote_execution::BatchUpdateBlobsRequest_Request::new();
+    good_request.set_digest(good_digest);
+    good_request.set_data(good.bytes());
+
+    let mut bad_request = bazel_protos::remote_execution::BatchUpdateBlobsRequest_Request::new();
+    bad_request.set_digest(bad_digest);
+    bad_request.set_data(good.bytes());
+
+    let mut req = bazel_protos::remote_execution::BatchUpdateBlobsRequest::new();
+    req.mut_requests().push(good_request);
+    req.mut_requests().push(bad_request);
+
+    let resp = client.batch_update_blobs(&req).unwrap();
+    let responses = resp.get_responses();
+    assert_eq!(responses.len(), 2);
+    assert_eq!(
+      responses[0].get_status().get_code(),
+      grpcio::RpcStatusCode::Ok as i32
+    );
+    assert_ne!(
+      responses[1].get_status().get_code(),
+      grpcio::RpcStatusCode::Ok as i32
+    );
+    assert!(cas.blobs.contains(&good.fingerprint()));
+  }
+
+  fn upload_resource_name(data: &TestData) -> String {
+    format!("/uploads/fake-uuid/blobs/{}/{}", data.fingerprint(), data.len())
+  }
+
+  #[test]
+  fn write_resumes_at_committed_offset_and_rejects_resume_past_it() {
+    let cas = StubCAS::empty();
+    let client = ByteStreamClient::new(channel(&cas));
+    let data = TestData::roland();
+    let name = upload_resource_name(&data);
+    let bytes = data.bytes();
+    let split = bytes.len() / 2;
+
+    // Write the first half, without finishing: the partial write is committed but not landed.
+    {
+      let (mut sink, receiver) = client.write().unwrap();
+      let mut req = bazel_protos::bytestream::WriteRequest::new();
+      req.set_resource_name(name.clone());
+      req.set_write_offset(0);
+      req.set_data(bytes.slice_to(split));
+      sink
+        .send((req, grpcio::WriteFlags::default()))
+        .wait()
+        .unwrap();
+      sink.close().wait().unwrap();
+      let resp = receiver.wait().unwrap();
+      assert_eq!(resp.get_committed_size(), split as i64);
+    }
+    assert_eq!(
+      cas.partial_writes.lock().unwrap().get(&name).unwrap().len(),
+      split
+    );
+
+    // Resuming past what's actually been committed should be rejected, not silently accepted.
+    {
+      let (mut sink, receiver) = client.write().unwrap();
+      let mut req = bazel_protos::bytestream::WriteRequest::new();
+      req.set_resource_name(name.clone());
+      req.set_write_offset((split + 1) as i64);
+      req.set_data(bytes.slice_from(split + 1));
+      req.set_finish_write(true);
+      sink
+        .send((req, grpcio::WriteFlags::default()))
+        .wait()
+        .unwrap();
+      sink.close().wait().unwrap();
+      assert!(receiver.wait().is_err());
+    }
+
+    // Resuming at the offset that was actually committed should succeed and land the blob.
+    {
+      let (mut sink, receiver) = client.write().unwrap();
+      let mut req = bazel_protos::bytestream::WriteRequest::new();
+      req.set_resource_name(name.clone());
+      req.set_write_offset(split as i64);
+      req.set_data(bytes.slice_from(split));
+      req.set_finish_write(true);
+      sink
+        .send((req, grpcio::WriteFlags::default()))
+        .wait()
+        .unwrap();
+      sink.close().wait().unwrap();
+      let resp = receiver.wait().unwrap();
+      assert_eq!(resp.get_committed_size(), bytes.len() as i64);
+    }
+    assert!(cas.blobs.contains(&data.fingerprint()));
+    assert!(!cas.partial_writes.lock().unwrap().contains_key(&name));
+  }
+
+  #[test]
+  #[should_panic(expected = "Fingerprint was incorrect")]
+  fn with_verified_content_rejects_mismatched_seed_content() {
+    let data = TestData::roland();
+    let wrong_fingerprint = Fingerprint::from_hex_string(&"0".repeat(64)).unwrap();
+    let mut blobs = HashMap::new();
+    blobs.insert(wrong_fingerprint, data.bytes());
+    StubCAS::with_verified_content(1024, blobs);
+  }
+
+  #[test]
+  fn write_with_verify_digests_rejects_mismatched_fingerprint() {
+    let cas = StubCAS::with_verified_content(1024, HashMap::new());
+    let client = ByteStreamClient::new(channel(&cas));
+    let data = TestData::roland();
+    let wrong_fingerprint = Fingerprint::from_hex_string(&"0".repeat(64)).unwrap();
+    let name = format!("/uploads/fake-uuid/blobs/{}/{}", wrong_fingerprint, data.len());
+
+    let (mut sink, receiver) = client.write().unwrap();
+    let mut req = bazel_protos::bytestream::WriteRequest::new();
+    req.set_resource_name(name);
+    req.set_write_offset(0);
+    req.set_data(data.bytes());
+    req.set_finish_write(true);
+    sink
+      .send((req, grpcio::WriteFlags::default()))
+      .wait()
+      .unwrap();
+    sink.close().wait().unwrap();
+
+    assert!(receiver.wait().is_err());
+    assert!(!cas.blobs.contains(&wrong_fingerprint));
+  }
+
+  ///
+  /// A self-signed cert/key pair for "localhost", suitable for both the server cert and (when a
+  /// test wants mutual TLS) the client root CA.
+  ///
+  fn self_signed_pem_pair() -> (Vec<u8>, Vec<u8>) {
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::{X509NameBuilder, X509};
+
+    let rsa = Rsa::generate(2048).unwrap();
+    let pkey = PKey::from_rsa(rsa).unwrap();
+
+    let mut name_builder = X509NameBuilder::new().unwrap();
+    name_builder.append_entry_by_text("CN", "localhost").unwrap();
+    let name = name_builder.build();
+
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(&pkey).unwrap();
+    builder
+      .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+      .unwrap();
+    builder
+      .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+      .unwrap();
+    builder.sign(&pkey, MessageDigest::sha256()).unwrap();
+    let cert = builder.build();
+
+    (
+      cert.to_pem().unwrap(),
+      pkey.private_key_to_pem_pkcs8().unwrap(),
+    )
+  }
+
+  ///
+  /// A self-signed CA cert/key pair, and a leaf cert/key pair signed by that CA - for testing
+  /// that mutual TLS accepts a client cert the configured root CA actually vouches for.
+  ///
+  fn ca_and_signed_leaf_pem_pairs() -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::{X509NameBuilder, X509};
+
+    let ca_rsa = Rsa::generate(2048).unwrap();
+    let ca_key = PKey::from_rsa(ca_rsa).unwrap();
+    let mut ca_name_builder = X509NameBuilder::new().unwrap();
+    ca_name_builder
+      .append_entry_by_text("CN", "stub-cas-test-ca")
+      .unwrap();
+    let ca_name = ca_name_builder.build();
+
+    let mut ca_builder = X509::builder().unwrap();
+    ca_builder.set_version(2).unwrap();
+    ca_builder.set_subject_name(&ca_name).unwrap();
+    ca_builder.set_issuer_name(&ca_name).unwrap();
+    ca_builder.set_pubkey(&ca_key).unwrap();
+    ca_builder
+      .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+      .unwrap();
+    ca_builder
+      .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+      .unwrap();
+    ca_builder.sign(&ca_key, MessageDigest::sha256()).unwrap();
+    let ca_cert = ca_builder.build();
+
+    let leaf_rsa = Rsa::generate(2048).unwrap();
+    let leaf_key = PKey::from_rsa(leaf_rsa).unwrap();
+    let mut leaf_name_builder = X509NameBuilder::new().unwrap();
+    leaf_name_builder
+      .append_entry_by_text("CN", "stub-cas-test-client")
+      .unwrap();
+    let leaf_name = leaf_name_builder.build();
+
+    let mut leaf_builder = X509::builder().unwrap();
+    leaf_builder.set_version(2).unwrap();
+    leaf_builder.set_subject_name(&leaf_name).unwrap();
+    leaf_builder.set_issuer_name(ca_cert.subject_name()).unwrap();
+    leaf_builder.set_pubkey(&leaf_key).unwrap();
+    leaf_builder
+      .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+      .unwrap();
+    leaf_builder
+      .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+      .unwrap();
+    leaf_builder.sign(&ca_key, MessageDigest::sha256()).unwrap();
+    let leaf_cert = leaf_builder.build();
+
+    (
+      ca_cert.to_pem().unwrap(),
+      leaf_cert.to_pem().unwrap(),
+      leaf_key.private_key_to_pem_pkcs8().unwrap(),
+    )
+  }
+
+  #[test]
+  fn with_tls_serves_over_tls_and_rejects_plaintext() {
+    let (server_cert, server_key) = self_signed_pem_pair();
+    let cas = StubCAS::with_tls(1024, HashMap::new(), server_cert.clone(), server_key, None);
+    assert!(cas.is_secure());
+
+    let env = Arc::new(grpcio::Environment::new(1));
+    let creds = grpcio::ChannelCredentialsBuilder::new()
+      .root_cert(server_cert)
+      .build();
+    let secure_channel = grpcio::ChannelBuilder::new(env.clone())
+      .override_ssl_target("localhost")
+      .secure_connect(&cas.address(), creds);
+    let secure_client = ContentAddressableStorageClient::new(secure_channel);
+    let req = bazel_protos::remote_execution::FindMissingBlobsRequest::new();
+    assert!(secure_client.find_missing_blobs(&req).is_ok());
+
+    let plaintext_channel = grpcio::ChannelBuilder::new(env).connect(&cas.address());
+    let plaintext_client = ContentAddressableStorageClient::new(plaintext_channel);
+    assert!(plaintext_client.find_missing_blobs(&req).is_err());
+  }
+
+  #[test]
+  fn with_tls_requires_client_cert_when_root_ca_given() {
+    let (server_cert, server_key) = self_signed_pem_pair();
+    let (client_root_ca_cert, client_cert, client_key) = ca_and_signed_leaf_pem_pairs();
+    let cas = StubCAS::with_tls(
+      1024,
+      HashMap::new(),
+      server_cert.clone(),
+      server_key,
+      Some(client_root_ca_cert),
+    );
+    let req = bazel_protos::remote_execution::FindMissingBlobsRequest::new();
+
+    // No client certificate presented: the mTLS handshake itself should fail, even though the
+    // client trusts the server's cert.
+    {
+      let env = Arc::new(grpcio::Environment::new(1));
+      let creds = grpcio::ChannelCredentialsBuilder::new()
+        .root_cert(server_cert.clone())
+        .build();
+      let channel = grpcio::ChannelBuilder::new(env)
+        .override_ssl_target("localhost")
+        .secure_connect(&cas.address(), creds);
+      let client = ContentAddressableStorageClient::new(channel);
+      assert!(client.find_missing_blobs(&req).is_err());
+    }
+
+    // A client presenting a cert signed by the configured root CA should be accepted. Without
+    // this case, a bug that made RequireAndVerifyClientCert reject every client - valid or not -
+    // would still pass this suite.
+    {
+      let env = Arc::new(grpcio::Environment::new(1));
+      let creds = grpcio::ChannelCredentialsBuilder::new()
+        .root_cert(server_cert)
+        .cert(client_cert, client_key)
+        .build();
+      let channel = grpcio::ChannelBuilder::new(env)
+        .override_ssl_target("localhost")
+        .secure_connect(&cas.address(), creds);
+      let client = ContentAddressableStorageClient::new(channel);
+      assert!(client.find_missing_blobs(&req).is_ok());
+    }
+  }
+
+  #[test]
+  fn sled_backend_persists_blobs_across_stub_cas_instances() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let data = TestData::roland();
+
+    {
+      let cas = StubCAS::with_sled_backend(dir.path(), 1024);
+      cas.blobs.put(data.fingerprint(), data.bytes());
+    }
+
+    // A fresh StubCAS pointed at the same path should see content written by the previous one,
+    // as opposed to a MemoryBlobService-backed StubCAS which would start out empty.
+    let cas = StubCAS::with_sled_backend(dir.path(), 1024);
+    assert_eq!(cas.blobs.get(&data.fingerprint()), Some(data.bytes()));
+  }
+
+  #[test]
+  fn get_tree_paginates_and_dedupes_shared_subdirectories() {
+    use bazel_protos::remote_execution::{Directory, DirectoryNode};
+    use protobuf::Message;
+
+    fn put_directory(
+      blobs: &mut HashMap<Fingerprint, Bytes>,
+      directory: Directory,
+    ) -> bazel_protos::remote_execution::Digest {
+      let bytes = Bytes::from(directory.write_to_bytes().unwrap());
+      let fingerprint = Fingerprint::from_bytes_unsafe(&bytes);
+      let mut digest = bazel_protos::remote_execution::Digest::new();
+      digest.set_hash(fingerprint.to_hex());
+      digest.set_size_bytes(bytes.len() as i64);
+      blobs.insert(fingerprint, bytes);
+      digest
+    }
+
+    let mut blobs = HashMap::new();
+
+    let leaf_digest = put_directory(&mut blobs, Directory::new());
+
+    let mut shared_node = DirectoryNode::new();
+    shared_node.set_name("shared".to_owned());
+    shared_node.set_digest(leaf_digest);
+
+    let mut child_a = Directory::new();
+    child_a.mut_directories().push(shared_node.clone());
+    let child_a_digest = put_directory(&mut blobs, child_a);
+
+    // Give child_b's entry a different name, so child_b's bytes (and digest) differ from
+    // child_a's even though both point at the same leaf.
+    shared_node.set_name("also_shared".to_owned());
+    let mut child_b = Directory::new();
+    child_b.mut_directories().push(shared_node);
+    let child_b_digest = put_directory(&mut blobs, child_b);
+
+    let mut node_a = DirectoryNode::new();
+    node_a.set_name("a".to_owned());
+    node_a.set_digest(child_a_digest);
+    let mut node_b = DirectoryNode::new();
+    node_b.set_name("b".to_owned());
+    node_b.set_digest(child_b_digest);
+
+    let mut root = Directory::new();
+    root.mut_directories().push(node_a);
+    root.mut_directories().push(node_b);
+    let root_digest = put_directory(&mut blobs, root);
+
+    let cas = StubCAS::with_unverified_content(1024, blobs);
+    let client = ContentAddressableStorageClient::new(channel(&cas));
+
+    let mut seen = Vec::new();
+    let mut page_token = String::new();
+    loop {
+      let mut req = bazel_protos::remote_execution::GetTreeRequest::new();
+      req.set_root_digest(root_digest.clone());
+      req.set_page_size(1);
+      req.set_page_token(page_token.clone());
+      let response = client
+        .get_tree(&req)
+        .unwrap()
+        .wait()
+        .next()
+        .unwrap()
+        .unwrap();
+      seen.extend(response.get_directories().to_vec());
+      if response.get_next_page_token().is_empty() {
+        break;
+      }
+      page_token = response.get_next_page_token().to_owned();
+    }
+
+    // root, child_a, child_b, and the shared leaf counted once despite being referenced by both
+    // children: four distinct directories, not five.
+    assert_eq!(seen.len(), 4);
+  }
+
+  #[test]
+  fn fault_config_fails_configured_attempts_then_succeeds() {
+    let fault = Fault::new(grpcio::RpcStatusCode::Unavailable, 2);
+    let cas = StubCAS::with_fault_config(
+      1024,
+      HashMap::new(),
+      FaultConfig {
+        find_missing_blobs: Some(fault.clone()),
+        ..FaultConfig::default()
+      },
+    );
+    let client = ContentAddressableStorageClient::new(channel(&cas));
+    let req = bazel_protos::remote_execution::FindMissingBlobsRequest::new();
+
+    assert!(client.find_missing_blobs(&req).is_err());
+    assert!(client.find_missing_blobs(&req).is_err());
+    assert!(client.find_missing_blobs(&req).is_ok());
+    assert_eq!(fault.attempt_count(), 3);
+  }
+
+  #[test]
+  fn find_missing_blobs_fault_counts_attempts_even_when_always_failing() {
+    // A negative chunk_size_bytes makes should_always_fail true. The Fault must still be
+    // consulted (and its attempt counter incremented) ahead of that check, the same way read and
+    // write already order things, or a retry-count assertion against an always_errors-style stub
+    // would silently see zero attempts.
+    let fault = Fault::new(grpcio::RpcStatusCode::Unavailable, 1);
+    let cas = StubCAS::with_fault_config(
+      -1,
+      HashMap::new(),
+      FaultConfig {
+        find_missing_blobs: Some(fault.clone()),
+        ..FaultConfig::default()
+      },
+    );
+    let client = ContentAddressableStorageClient::new(channel(&cas));
+    let req = bazel_protos::remote_execution::FindMissingBlobsRequest::new();
+
+    assert!(client.find_missing_blobs(&req).is_err());
+    assert_eq!(fault.attempt_count(), 1);
+    assert!(client.find_missing_blobs(&req).is_err());
+    assert_eq!(fault.attempt_count(), 2);
   }
 }